@@ -1,19 +1,35 @@
-use std::{fmt::Display, sync::Arc};
+use std::{
+    any::{Any, TypeId},
+    cell::Cell,
+    collections::HashMap,
+    fmt::Display,
+    future::Future,
+    sync::Arc,
+    time::Duration,
+};
 
+use rand::Rng;
 use rig::{
     agent::Agent,
-    completion::{CompletionModel, Prompt},
-    extractor::Extractor,
+    completion::{CompletionModel, Prompt, PromptError},
+    extractor::{ExtractionError, Extractor},
 };
 use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
+use tokio::{
+    sync::{Mutex, Semaphore},
+    time::{error::Elapsed, sleep, timeout, Instant},
+};
 
-trait Service {
-    async fn call(&mut self, input: String) -> String;
+trait Service<Request> {
+    type Response;
+    type Error;
+
+    async fn call(&mut self, req: Request) -> Result<Self::Response, Self::Error>;
 }
 
 trait Layer<S> {
-    type Service: Service;
+    type Service;
 
     fn layer(&self, inner: S) -> Self::Service;
 }
@@ -45,6 +61,55 @@ impl<L> ServiceBuilder<L> {
     }
 }
 
+/// Returns a new [`LayerFn`] that implements [`Layer`] by calling the given closure.
+///
+/// Handy for one-off wrappers that don't justify a dedicated `Layer` struct.
+pub fn layer_fn<F>(f: F) -> LayerFn<F> {
+    LayerFn { f }
+}
+
+/// A [`Layer`] implemented by a closure, produced by [`layer_fn`].
+pub struct LayerFn<F> {
+    f: F,
+}
+
+impl<F, S, Out> Layer<S> for LayerFn<F>
+where
+    F: Fn(S) -> Out,
+{
+    type Service = Out;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        (self.f)(inner)
+    }
+}
+
+/// Returns a new [`ServiceFn`] that implements [`Service`] by calling the given closure.
+///
+/// Lets trivial transforms be expressed as an `async FnMut(Request)` without the
+/// boilerplate of a dedicated `Service` struct.
+pub fn service_fn<F>(f: F) -> ServiceFn<F> {
+    ServiceFn { f }
+}
+
+/// A [`Service`] implemented by an async closure, produced by [`service_fn`].
+pub struct ServiceFn<F> {
+    f: F,
+}
+
+impl<F, Request, Fut, Response, Error> Service<Request> for ServiceFn<F>
+where
+    F: FnMut(Request) -> Fut,
+    Fut: Future<Output = Result<Response, Error>>,
+{
+    type Response = Response;
+    type Error = Error;
+
+    async fn call(&mut self, req: Request) -> Result<Self::Response, Self::Error> {
+        (self.f)(req).await
+    }
+}
+
 struct Stack<Inner, Outer> {
     inner: Inner,
     outer: Outer,
@@ -75,16 +140,16 @@ struct ExtractService<M: CompletionModel, T: JsonSchema + for<'a> Deserialize<'a
     extractor: Extractor<M, T>,
 }
 
-impl<M, T> Service for ExtractService<M, T>
+impl<M, T> Service<String> for ExtractService<M, T>
 where
     M: CompletionModel,
-    T: Display + JsonSchema + for<'a> Deserialize<'a> + Send + Sync,
-    T: Serialize,
+    T: JsonSchema + for<'a> Deserialize<'a> + Send + Sync,
 {
-    async fn call(&mut self, input: String) -> String {
-        let res = self.extractor.extract(&input).await.unwrap();
+    type Response = T;
+    type Error = ExtractionError;
 
-        serde_json::to_string_pretty(&res).unwrap()
+    async fn call(&mut self, input: String) -> Result<Self::Response, Self::Error> {
+        self.extractor.extract(&input).await
     }
 }
 
@@ -93,13 +158,21 @@ struct AgentLayerService<M: CompletionModel, S> {
     agent: Arc<Agent<M>>,
 }
 
-impl<M: CompletionModel, S: Service> Service for AgentLayerService<M, S> {
-    async fn call(&mut self, input: String) -> String {
-        let res = self.inner.call(input).await;
+impl<M, S, Request> Service<Request> for AgentLayerService<M, S>
+where
+    M: CompletionModel,
+    S: Service<Request, Response = String>,
+    S::Error: From<PromptError>,
+{
+    type Response = String;
+    type Error = S::Error;
+
+    async fn call(&mut self, req: Request) -> Result<Self::Response, Self::Error> {
+        let res = self.inner.call(req).await?;
 
-        let next = self.agent.prompt(res.as_ref()).await.unwrap();
+        let next = self.agent.prompt(res.as_ref()).await?;
 
-        next
+        Ok(next)
     }
 }
 
@@ -115,7 +188,7 @@ impl<M: CompletionModel> AgentLayer<M> {
     }
 }
 
-impl<S: Service, M: CompletionModel> Layer<S> for AgentLayer<M> {
+impl<S, M: CompletionModel> Layer<S> for AgentLayer<M> {
     type Service = AgentLayerService<M, S>;
 
     fn layer(&self, inner: S) -> Self::Service {
@@ -144,7 +217,7 @@ impl<M: CompletionModel> From<Agent<M>> for AgentService<M> {
 
 struct LoggingMiddleware;
 
-impl<S: Service> Layer<S> for LoggingMiddleware {
+impl<S> Layer<S> for LoggingMiddleware {
     type Service = LoggingService<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
@@ -157,7 +230,7 @@ struct LoggingService<S> {
 }
 
 // Base case: Allow `()` as a no-op layer
-impl<S: Service> Layer<S> for () {
+impl<S> Layer<S> for () {
     type Service = S;
 
     fn layer(&self, inner: S) -> Self::Service {
@@ -165,25 +238,468 @@ impl<S: Service> Layer<S> for () {
     }
 }
 
-impl<S> Service for LoggingService<S>
+impl<S, Request> Service<Request> for LoggingService<S>
 where
-    S: Service,
+    S: Service<Request>,
+    S::Response: Display,
+    S::Error: Display,
 {
-    async fn call(&mut self, input: String) -> String {
+    type Response = S::Response;
+    type Error = S::Error;
+
+    async fn call(&mut self, req: Request) -> Result<Self::Response, Self::Error> {
         println!("Before a message!");
-        let res = self.inner.call(input).await;
-        println!("LLM response: {res}");
+        match self.inner.call(req).await {
+            Ok(res) => {
+                println!("LLM response: {res}");
+                Ok(res)
+            }
+            Err(e) => {
+                println!("Service error: {e}");
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<T: CompletionModel> Service<String> for AgentService<T> {
+    type Response = String;
+    type Error = PromptError;
 
-        res.to_string()
+    async fn call(&mut self, input: String) -> Result<Self::Response, Self::Error> {
+        self.agent.prompt(input).await
     }
 }
 
-impl<T: CompletionModel> Service for AgentService<T> {
-    async fn call(&mut self, input: String) -> String {
-        let input = input.to_string();
-        let res = self.agent.prompt(input).await.unwrap();
+/// Extra context prepended to the prompt, injected into a request's
+/// [`Extensions`] by an [`AddExtensionLayer`].
+#[derive(Clone)]
+pub struct PromptContext(pub String);
+
+impl<T: CompletionModel> Service<Request<String>> for AgentService<T> {
+    type Response = String;
+    type Error = PromptError;
+
+    async fn call(&mut self, req: Request<String>) -> Result<Self::Response, Self::Error> {
+        // Honour any request-scoped context an upstream `AddExtensionLayer`
+        // stashed in the extensions map, so middleware can steer the prompt
+        // without threading state through this service's constructor.
+        let prompt = match req.extensions().get::<PromptContext>() {
+            Some(PromptContext(ctx)) => format!("{ctx}\n\n{}", req.prompt()),
+            None => req.prompt().clone(),
+        };
+
+        self.agent.prompt(prompt).await
+    }
+}
+
+/// Decides whether a failed `call` should be retried, and how long to wait first.
+///
+/// Returning `None` stops retrying (e.g. a `400` that will never succeed), while
+/// `Some(delay)` asks the [`RetryService`] to sleep for `delay` and try again.
+trait Policy<E> {
+    fn should_retry(&self, err: &E) -> Option<Duration>;
+}
+
+/// Exponential backoff with full jitter.
+///
+/// The nth retry is bounded at `min(max_delay, min_delay * multiplier^n)`; the
+/// actual sleep is then sampled uniformly in `[0, bound]` so that many callers
+/// hitting the same provider don't wake up in lockstep.
+///
+/// # Warning
+///
+/// This policy does **not** inspect the error: it retries *every* failure up to
+/// `max_retries`, including fatal ones like a `400`. To classify errors — e.g.
+/// retry `429`/`5xx`/timeouts but give up immediately on a `400` — implement a
+/// custom [`Policy`] whose `should_retry` returns `None` for fatal errors.
+#[derive(Clone)]
+pub struct ExponentialBackoff {
+    min_delay: Duration,
+    max_delay: Duration,
+    max_retries: u32,
+    multiplier: f64,
+    attempt: Cell<u32>,
+}
+
+impl ExponentialBackoff {
+    pub fn new(min_delay: Duration, max_delay: Duration, max_retries: u32, multiplier: f64) -> Self {
+        Self {
+            min_delay,
+            max_delay,
+            max_retries,
+            multiplier,
+            attempt: Cell::new(0),
+        }
+    }
+}
+
+impl<E> Policy<E> for ExponentialBackoff {
+    fn should_retry(&self, _err: &E) -> Option<Duration> {
+        let n = self.attempt.get();
+        if n >= self.max_retries {
+            return None;
+        }
+        self.attempt.set(n + 1);
+
+        let bound = self
+            .min_delay
+            .mul_f64(self.multiplier.powi(n as i32))
+            .min(self.max_delay);
+
+        // Full jitter: sample the real sleep uniformly in `[0, bound]`.
+        let millis = rand::thread_rng().gen_range(0..=bound.as_millis() as u64);
+        Some(Duration::from_millis(millis))
+    }
+}
+
+struct RetryLayer<P> {
+    policy: P,
+}
 
-        res
+impl<P> RetryLayer<P> {
+    fn new(policy: P) -> Self {
+        Self { policy }
+    }
+}
+
+impl<S, P: Clone> Layer<S> for RetryLayer<P> {
+    type Service = RetryService<S, P>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RetryService {
+            inner,
+            policy: self.policy.clone(),
+        }
+    }
+}
+
+struct RetryService<S, P> {
+    inner: S,
+    policy: P,
+}
+
+impl<S, P, Request> Service<Request> for RetryService<S, P>
+where
+    S: Service<Request>,
+    P: Policy<S::Error> + Clone,
+    Request: Clone,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    async fn call(&mut self, req: Request) -> Result<Self::Response, Self::Error> {
+        // Each call gets a fresh policy so per-attempt state (e.g. the backoff
+        // counter) doesn't leak between independent requests.
+        let policy = self.policy.clone();
+
+        loop {
+            match self.inner.call(req.clone()).await {
+                Ok(res) => return Ok(res),
+                Err(err) => match policy.should_retry(&err) {
+                    Some(delay) => sleep(delay).await,
+                    None => return Err(err),
+                },
+            }
+        }
+    }
+}
+
+struct ConcurrencyLimitLayer {
+    semaphore: Arc<Semaphore>,
+}
+
+impl ConcurrencyLimitLayer {
+    fn new(max: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max)),
+        }
+    }
+}
+
+impl<S> Layer<S> for ConcurrencyLimitLayer {
+    type Service = ConcurrencyLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConcurrencyLimitService {
+            inner,
+            semaphore: Arc::clone(&self.semaphore),
+        }
+    }
+}
+
+struct ConcurrencyLimitService<S> {
+    inner: S,
+    semaphore: Arc<Semaphore>,
+}
+
+impl<S, Request> Service<Request> for ConcurrencyLimitService<S>
+where
+    S: Service<Request>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    async fn call(&mut self, req: Request) -> Result<Self::Response, Self::Error> {
+        // Hold a permit for the whole call so at most `max` requests are ever
+        // in flight against the inner service. The semaphore is never closed.
+        let _permit = self.semaphore.acquire().await.expect("semaphore closed");
+
+        self.inner.call(req).await
+    }
+}
+
+struct RateLimitLayer {
+    num: u64,
+    per: Duration,
+}
+
+impl RateLimitLayer {
+    /// Allows `num` requests per `per`.
+    ///
+    /// Panics if `num` is zero: a bucket with no capacity could never hand out
+    /// a token, and the wait computation divides by `num`.
+    fn new(num: u64, per: Duration) -> Self {
+        assert!(num > 0, "RateLimitLayer requires num > 0");
+        Self { num, per }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            num: self.num,
+            per: self.per,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: self.num as f64,
+                last: Instant::now(),
+            })),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+struct RateLimitService<S> {
+    inner: S,
+    num: u64,
+    per: Duration,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl<S, Request> Service<Request> for RateLimitService<S>
+where
+    S: Service<Request>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    async fn call(&mut self, req: Request) -> Result<Self::Response, Self::Error> {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().await;
+
+                // Continuously refill: `tokens += elapsed / per * num`, capped at `num`.
+                let now = Instant::now();
+                let elapsed = now.duration_since(bucket.last).as_secs_f64();
+                let refill = elapsed / self.per.as_secs_f64() * self.num as f64;
+                if refill > 0.0 {
+                    bucket.tokens = (bucket.tokens + refill).min(self.num as f64);
+                    bucket.last = now;
+                }
+
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    None
+                } else {
+                    // Sleep just long enough for one token to accrue, then re-check.
+                    let needed = 1.0 - bucket.tokens;
+                    Some(Duration::from_secs_f64(needed * self.per.as_secs_f64() / self.num as f64))
+                }
+            };
+
+            match wait {
+                Some(delay) => sleep(delay).await,
+                None => break,
+            }
+        }
+
+        self.inner.call(req).await
+    }
+}
+
+/// A typed, heterogeneous map keyed by the stored value's type.
+///
+/// Mirrors `http::Extensions`: at most one value of any given type is stored,
+/// and values are retrieved by their type rather than by a string key.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts a value, returning the previous one of the same type if any.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, val: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(val))
+            .and_then(|prev| prev.downcast().ok().map(|boxed| *boxed))
+    }
+
+    /// Returns a reference to the stored value of type `T`, if present.
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|boxed| boxed.downcast_ref())
+    }
+}
+
+/// A prompt paired with a request-scoped [`Extensions`] map.
+///
+/// Lets middleware thread per-request state — a conversation id, an
+/// `Arc<VectorStore>`, a tracing span — without routing it through every
+/// service's constructor.
+pub struct Request<T> {
+    prompt: T,
+    extensions: Extensions,
+}
+
+impl<T> Request<T> {
+    pub fn new(prompt: T) -> Self {
+        Self {
+            prompt,
+            extensions: Extensions::new(),
+        }
+    }
+
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    pub fn prompt(&self) -> &T {
+        &self.prompt
+    }
+
+    pub fn into_inner(self) -> T {
+        self.prompt
+    }
+}
+
+impl<T> From<T> for Request<T> {
+    fn from(prompt: T) -> Self {
+        Self::new(prompt)
+    }
+}
+
+struct AddExtensionLayer<T> {
+    value: T,
+}
+
+impl<T: Clone> AddExtensionLayer<T> {
+    fn new(value: T) -> Self {
+        Self { value }
+    }
+}
+
+impl<S, T: Clone> Layer<S> for AddExtensionLayer<T> {
+    type Service = AddExtensionService<S, T>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AddExtensionService {
+            inner,
+            value: self.value.clone(),
+        }
+    }
+}
+
+struct AddExtensionService<S, T> {
+    inner: S,
+    value: T,
+}
+
+impl<S, T, P> Service<Request<P>> for AddExtensionService<S, T>
+where
+    S: Service<Request<P>>,
+    T: Clone + Send + Sync + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    async fn call(&mut self, mut req: Request<P>) -> Result<Self::Response, Self::Error> {
+        req.extensions_mut().insert(self.value.clone());
+
+        self.inner.call(req).await
+    }
+}
+
+/// Error returned by [`TimeoutService`]: either the inner service failed, or the
+/// call exceeded the configured duration.
+pub enum TimeoutError<E> {
+    Timeout(Elapsed),
+    Inner(E),
+}
+
+impl<E: Display> Display for TimeoutError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimeoutError::Timeout(elapsed) => write!(f, "{elapsed}"),
+            TimeoutError::Inner(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+struct TimeoutLayer {
+    timeout: Duration,
+}
+
+impl TimeoutLayer {
+    fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+}
+
+impl<S> Layer<S> for TimeoutLayer {
+    type Service = TimeoutService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TimeoutService {
+            inner,
+            timeout: self.timeout,
+        }
+    }
+}
+
+struct TimeoutService<S> {
+    inner: S,
+    timeout: Duration,
+}
+
+impl<S, Request> Service<Request> for TimeoutService<S>
+where
+    S: Service<Request>,
+{
+    type Response = S::Response;
+    type Error = TimeoutError<S::Error>;
+
+    async fn call(&mut self, req: Request) -> Result<Self::Response, Self::Error> {
+        match timeout(self.timeout, self.inner.call(req)).await {
+            Ok(Ok(res)) => Ok(res),
+            Ok(Err(err)) => Err(TimeoutError::Inner(err)),
+            Err(elapsed) => Err(TimeoutError::Timeout(elapsed)),
+        }
     }
 }
 
@@ -191,7 +707,88 @@ impl<T: CompletionModel> Service for AgentService<T> {
 mod tests {
     use rig::providers::openai;
 
-    use crate::{AgentLayer, AgentService, LoggingMiddleware, Service, ServiceBuilder};
+    use std::time::Duration;
+
+    use crate::{
+        service_fn, AgentLayer, AgentService, ExponentialBackoff, Extensions, Layer,
+        LoggingMiddleware, Policy, RateLimitLayer, Service, ServiceBuilder,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit_refills_and_caps() {
+        use tokio::time::Instant;
+
+        // 2 requests per 100ms.
+        let mut svc =
+            RateLimitLayer::new(2, Duration::from_millis(100)).layer(service_fn(|()| async {
+                Ok::<(), ()>(())
+            }));
+
+        // The bucket starts full, so the first two calls go through instantly.
+        let start = Instant::now();
+        svc.call(()).await.unwrap();
+        svc.call(()).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // The third call blocks until one token refills: 1 / (2 per 100ms) = 50ms.
+        svc.call(()).await.unwrap();
+        let waited = start.elapsed();
+        assert!(
+            (Duration::from_millis(49)..=Duration::from_millis(51)).contains(&waited),
+            "expected ~50ms wait, got {waited:?}"
+        );
+
+        // After a long idle the bucket refills but is capped at `num`: only two
+        // calls are instant, proving the cap (uncapped it would bank ~20 tokens).
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let mark = Instant::now();
+        svc.call(()).await.unwrap();
+        svc.call(()).await.unwrap();
+        assert_eq!(mark.elapsed(), Duration::ZERO);
+        svc.call(()).await.unwrap();
+        assert!(mark.elapsed() >= Duration::from_millis(49));
+    }
+
+    #[test]
+    fn backoff_bounds_and_stops() {
+        let min = Duration::from_millis(100);
+        let max = Duration::from_millis(500);
+        let policy = ExponentialBackoff::new(min, max, 4, 2.0);
+
+        // The nth sampled delay stays within `[0, min(max, min*2^n)]` (full jitter).
+        for n in 0..4 {
+            let bound = min.mul_f64(2f64.powi(n)).min(max);
+            let delay = policy
+                .should_retry(&())
+                .expect("should retry before exhausting max_retries");
+            assert!(delay <= bound, "delay {delay:?} exceeded bound {bound:?}");
+        }
+
+        // Once `max_retries` attempts are used up, retrying stops.
+        assert!(policy.should_retry(&()).is_none());
+    }
+
+    #[test]
+    fn extensions_round_trip() {
+        let mut ext = Extensions::new();
+
+        // Absent until inserted.
+        assert!(ext.get::<String>().is_none());
+
+        // Insert returns no previous value, then the value reads back by type.
+        assert!(ext.insert("conversation-1".to_string()).is_none());
+        assert_eq!(ext.get::<String>().map(String::as_str), Some("conversation-1"));
+
+        // Inserting the same type overwrites and hands back the old value.
+        let prev = ext.insert("conversation-2".to_string());
+        assert_eq!(prev.as_deref(), Some("conversation-1"));
+        assert_eq!(ext.get::<String>().map(String::as_str), Some("conversation-2"));
+
+        // Distinct types are stored independently.
+        ext.insert(7u32);
+        assert_eq!(ext.get::<u32>(), Some(&7));
+        assert_eq!(ext.get::<String>().map(String::as_str), Some("conversation-2"));
+    }
 
     #[tokio::test]
     async fn macro_works() {
@@ -208,7 +805,7 @@ mod tests {
             .layer(LoggingMiddleware)
             .build(agent_service);
 
-        thing.call("Hello world!".to_string()).await;
+        thing.call("Hello world!".to_string()).await.unwrap();
     }
 
     #[tokio::test]
@@ -232,6 +829,9 @@ mod tests {
             .layer(agent_layer)
             .build(agent_service);
 
-        println!("{}", service.call("Hello world!".into()).await)
+        println!(
+            "{}",
+            service.call("Hello world!".to_string()).await.unwrap()
+        )
     }
 }